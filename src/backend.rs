@@ -0,0 +1,434 @@
+// SPDX-License-Identifier: Apache-2.0
+
+//! OS-specific plumbing for talking to a single CPU's MSR interface.
+//!
+//! [`Handle`] is the only thing the rest of the crate sees; everything else
+//! in here is a per-platform implementation detail picked with `cfg`. Linux
+//! keeps the historical `/dev/cpu/<cpu>/msr` seek+read/write path; the BSDs,
+//! OpenBSD and macOS map onto their own device/ioctl equivalents the way
+//! `flashrom` does.
+
+use crate::{MsrError, Result};
+
+/// Opens, reads and writes a single CPU's MSR interface.
+pub(crate) trait Backend: Sized {
+    /// Open the MSR interface for `cpu`.
+    fn open(cpu: u16) -> Result<Self>;
+
+    /// Read the register at `reg`.
+    fn read(&mut self, reg: u32) -> Result<u64>;
+
+    /// Write `value` to the register at `reg`.
+    fn write(&self, reg: u32, value: u64) -> Result<()>;
+
+    /// Read `count` consecutive registers starting at `reg`.
+    ///
+    /// The default implementation issues one access per register;
+    /// backends that can batch the underlying syscall override this.
+    fn read_range(&mut self, reg: u32, count: usize) -> Result<Vec<u64>> {
+        (0..count as u32)
+            .map(|offset| self.read(checked_offset(reg, offset)?))
+            .collect()
+    }
+
+    /// Write `values` starting at `reg`. See [`Backend::read_range`].
+    fn write_range(&self, reg: u32, values: &[u64]) -> Result<()> {
+        for (offset, value) in values.iter().enumerate() {
+            self.write(checked_offset(reg, offset as u32)?, *value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Add `offset` to `reg`, rejecting ranges that would overflow a `u32`
+/// register address instead of silently wrapping.
+fn checked_offset(reg: u32, offset: u32) -> Result<u32> {
+    reg.checked_add(offset).ok_or(MsrError::InvalidRange)
+}
+
+/// Enumerate every CPU that exposes an MSR interface on this platform.
+pub(crate) fn enumerate_cpus() -> Result<Vec<u16>> {
+    platform::enumerate_cpus()
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::{Backend, MsrError, Result};
+    use std::{
+        fs::{File, OpenOptions},
+        io::{Read, Seek, SeekFrom},
+        os::unix::fs::FileExt,
+        path::Path,
+    };
+
+    /// A single CPU's `/dev/cpu/<cpu>/msr` file.
+    pub(crate) struct Handle(File);
+
+    #[cfg(test)]
+    impl Handle {
+        /// Wrap an already-open file, bypassing the `/dev/cpu` discovery
+        /// `open` performs, so tests can exercise the layers above
+        /// `Backend` without a real MSR device.
+        pub(crate) fn from_file(file: File) -> Self {
+            Self(file)
+        }
+    }
+
+    impl Backend for Handle {
+        fn open(cpu: u16) -> Result<Self> {
+            let path = format!("/dev/cpu/{cpu}/msr");
+            if !Path::new(&path).exists() {
+                return Err(MsrError::MissingKernelModule);
+            }
+            Ok(Self(OpenOptions::new().read(true).write(true).open(path)?))
+        }
+
+        fn read(&mut self, reg: u32) -> Result<u64> {
+            let mut buffer = [0u8; 8];
+            self.0.seek(SeekFrom::Start(reg.into()))?;
+            self.0.read_exact(&mut buffer)?;
+            Ok(u64::from_ne_bytes(buffer))
+        }
+
+        fn write(&self, reg: u32, value: u64) -> Result<()> {
+            self.0.write_all_at(&value.to_ne_bytes(), reg.into())?;
+            Ok(())
+        }
+
+        fn read_range(&mut self, reg: u32, count: usize) -> Result<Vec<u64>> {
+            // The msr device treats a larger read as `count` consecutive
+            // 8-byte registers starting at the seeked offset, so this is a
+            // single syscall instead of `count` of them.
+            let mut buffer = vec![0u8; count * 8];
+            self.0.seek(SeekFrom::Start(reg.into()))?;
+            self.0.read_exact(&mut buffer)?;
+            Ok(buffer
+                .chunks_exact(8)
+                .map(|chunk| u64::from_ne_bytes(chunk.try_into().expect("chunk is 8 bytes")))
+                .collect())
+        }
+
+        fn write_range(&self, reg: u32, values: &[u64]) -> Result<()> {
+            let mut buffer = Vec::with_capacity(values.len() * 8);
+            for value in values {
+                buffer.extend_from_slice(&value.to_ne_bytes());
+            }
+            self.0.write_all_at(&buffer, reg.into())?;
+            Ok(())
+        }
+    }
+
+    pub(crate) fn enumerate_cpus() -> Result<Vec<u16>> {
+        let mut cpus = Vec::new();
+        for entry in std::fs::read_dir("/dev/cpu")? {
+            let entry = entry?;
+            let Some(cpu) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<u16>().ok())
+            else {
+                continue;
+            };
+            if entry.path().join("msr").exists() {
+                cpus.push(cpu);
+            }
+        }
+        cpus.sort_unstable();
+        if cpus.is_empty() {
+            return Err(MsrError::MissingKernelModule);
+        }
+        Ok(cpus)
+    }
+}
+
+#[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+mod platform {
+    use super::{Backend, MsrError, Result};
+    use std::{
+        fs::{File, OpenOptions},
+        os::unix::io::AsRawFd,
+        path::Path,
+    };
+
+    /// Mirrors `cpuctl_msr_args_t` from `<machine/cpufunc.h>`.
+    #[repr(C)]
+    struct CpuctlMsrArgs {
+        msr: i32,
+        data: u64,
+    }
+
+    const fn iowr(group: u8, num: u8, size: usize) -> u64 {
+        const IOC_INOUT: u64 = 0x8000_0000 | 0x4000_0000;
+        IOC_INOUT | ((size as u64 & 0x1fff) << 16) | ((group as u64) << 8) | num as u64
+    }
+
+    // From `<machine/cpuctl.h>`: CPUCTL_RDMSR / CPUCTL_WRMSR.
+    const CPUCTL_RDMSR: u64 = iowr(b'c', 1, std::mem::size_of::<CpuctlMsrArgs>());
+    const CPUCTL_WRMSR: u64 = iowr(b'c', 2, std::mem::size_of::<CpuctlMsrArgs>());
+
+    extern "C" {
+        fn ioctl(fd: i32, request: u64, ...) -> i32;
+    }
+
+    /// A single CPU's `/dev/cpuctl<cpu>` device, as used by `cpucontrol(8)`.
+    pub(crate) struct Handle(File);
+
+    impl Backend for Handle {
+        fn open(cpu: u16) -> Result<Self> {
+            let path = format!("/dev/cpuctl{cpu}");
+            if !Path::new(&path).exists() {
+                return Err(MsrError::MissingKernelModule);
+            }
+            Ok(Self(OpenOptions::new().read(true).write(true).open(path)?))
+        }
+
+        fn read(&mut self, reg: u32) -> Result<u64> {
+            let mut args = CpuctlMsrArgs { msr: reg as i32, data: 0 };
+            let rc = unsafe { ioctl(self.0.as_raw_fd(), CPUCTL_RDMSR, &mut args) };
+            if rc != 0 {
+                return Err(MsrError::IoError(std::io::Error::last_os_error()));
+            }
+            Ok(args.data)
+        }
+
+        fn write(&self, reg: u32, value: u64) -> Result<()> {
+            let args = CpuctlMsrArgs { msr: reg as i32, data: value };
+            let rc = unsafe { ioctl(self.0.as_raw_fd(), CPUCTL_WRMSR, &args) };
+            if rc != 0 {
+                return Err(MsrError::IoError(std::io::Error::last_os_error()));
+            }
+            Ok(())
+        }
+    }
+
+    pub(crate) fn enumerate_cpus() -> Result<Vec<u16>> {
+        let mut cpus = Vec::new();
+        for entry in std::fs::read_dir("/dev")? {
+            let entry = entry?;
+            let Some(cpu) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix("cpuctl"))
+                .and_then(|rest| rest.parse::<u16>().ok())
+            else {
+                continue;
+            };
+            cpus.push(cpu);
+        }
+        cpus.sort_unstable();
+        if cpus.is_empty() {
+            return Err(MsrError::MissingKernelModule);
+        }
+        Ok(cpus)
+    }
+}
+
+#[cfg(target_os = "openbsd")]
+mod platform {
+    use super::{Backend, MsrError, Result};
+    use std::{
+        fs::{File, OpenOptions},
+        os::unix::io::AsRawFd,
+        path::Path,
+    };
+
+    /// Mirrors `struct cpu_msr` from `<machine/cpuio.h>`.
+    #[repr(C)]
+    struct CpuMsr {
+        msr: u32,
+        data: u64,
+    }
+
+    const fn iowr(group: u8, num: u8, size: usize) -> u64 {
+        const IOC_INOUT: u64 = 0x8000_0000 | 0x4000_0000;
+        IOC_INOUT | ((size as u64 & 0x1fff) << 16) | ((group as u64) << 8) | num as u64
+    }
+
+    // From `<machine/cpuio.h>`: CPUIOC_MSRGET / CPUIOC_MSRSET.
+    const CPUIOC_MSRGET: u64 = iowr(b'c', 1, std::mem::size_of::<CpuMsr>());
+    const CPUIOC_MSRSET: u64 = iowr(b'c', 2, std::mem::size_of::<CpuMsr>());
+
+    extern "C" {
+        fn ioctl(fd: i32, request: u64, ...) -> i32;
+    }
+
+    /// A single CPU's `/dev/cpu<cpu>` ioctl interface.
+    pub(crate) struct Handle(File);
+
+    impl Backend for Handle {
+        fn open(cpu: u16) -> Result<Self> {
+            let path = format!("/dev/cpu{cpu}");
+            if !Path::new(&path).exists() {
+                return Err(MsrError::MissingKernelModule);
+            }
+            Ok(Self(OpenOptions::new().read(true).write(true).open(path)?))
+        }
+
+        fn read(&mut self, reg: u32) -> Result<u64> {
+            let mut request = CpuMsr { msr: reg, data: 0 };
+            let rc = unsafe { ioctl(self.0.as_raw_fd(), CPUIOC_MSRGET, &mut request) };
+            if rc != 0 {
+                return Err(MsrError::IoError(std::io::Error::last_os_error()));
+            }
+            Ok(request.data)
+        }
+
+        fn write(&self, reg: u32, value: u64) -> Result<()> {
+            let request = CpuMsr { msr: reg, data: value };
+            let rc = unsafe { ioctl(self.0.as_raw_fd(), CPUIOC_MSRSET, &request) };
+            if rc != 0 {
+                return Err(MsrError::IoError(std::io::Error::last_os_error()));
+            }
+            Ok(())
+        }
+    }
+
+    pub(crate) fn enumerate_cpus() -> Result<Vec<u16>> {
+        let mut cpus = Vec::new();
+        for entry in std::fs::read_dir("/dev")? {
+            let entry = entry?;
+            let Some(cpu) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix("cpu"))
+                .and_then(|rest| rest.parse::<u16>().ok())
+            else {
+                continue;
+            };
+            cpus.push(cpu);
+        }
+        cpus.sort_unstable();
+        if cpus.is_empty() {
+            return Err(MsrError::MissingKernelModule);
+        }
+        Ok(cpus)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::{Backend, MsrError, Result};
+    use std::{fs::File, fs::OpenOptions, os::unix::io::AsRawFd, path::Path};
+
+    /// Mirrors the `msr_t { lo, hi }` wire format used by the DirectHW
+    /// kernel extension's `rdmsr`/`wrmsr` user-client calls.
+    #[repr(C)]
+    struct DirectHwMsr {
+        ecx: u32,
+        lo: u32,
+        hi: u32,
+    }
+
+    const fn iowr(group: u8, num: u8, size: usize) -> u64 {
+        const IOC_INOUT: u64 = 0x8000_0000 | 0x4000_0000;
+        IOC_INOUT | ((size as u64 & 0x1fff) << 16) | ((group as u64) << 8) | num as u64
+    }
+
+    const DIRECTHW_RDMSR: u64 = iowr(b'd', 1, std::mem::size_of::<DirectHwMsr>());
+    const DIRECTHW_WRMSR: u64 = iowr(b'd', 2, std::mem::size_of::<DirectHwMsr>());
+
+    extern "C" {
+        fn ioctl(fd: i32, request: u64, ...) -> i32;
+    }
+
+    // From `<mach/thread_policy.h>`: THREAD_AFFINITY_POLICY and its
+    // `thread_affinity_policy_data_t { affinity_tag: i32 }` payload.
+    const THREAD_AFFINITY_POLICY: i32 = 4;
+    const THREAD_AFFINITY_POLICY_COUNT: u32 = 1;
+
+    extern "C" {
+        fn pthread_self() -> usize;
+        fn pthread_mach_thread_np(thread: usize) -> u32;
+        fn thread_policy_set(thread: u32, flavor: i32, policy_info: *mut i32, count: u32) -> i32;
+    }
+
+    /// Tag the calling thread with `cpu` as its affinity set.
+    ///
+    /// macOS gives user space no way to *require* a thread run on a given
+    /// core; `THREAD_AFFINITY_POLICY` is only a hint the scheduler is free
+    /// to ignore, so `rdmsr`/`wrmsr` against a specific `cpu` here is
+    /// best-effort, not a guarantee. See the caveat on [`crate::Msr::new`].
+    fn pin_to_cpu(cpu: u16) -> Result<()> {
+        let mut affinity_tag = cpu as i32;
+        let thread = unsafe { pthread_mach_thread_np(pthread_self()) };
+        let rc = unsafe {
+            thread_policy_set(
+                thread,
+                THREAD_AFFINITY_POLICY,
+                &mut affinity_tag,
+                THREAD_AFFINITY_POLICY_COUNT,
+            )
+        };
+        if rc != 0 {
+            return Err(MsrError::IoError(std::io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// The shared `/dev/DirectHW` device. DirectHW has no notion of a
+    /// per-CPU path, so `read`/`write` best-effort pin the calling thread
+    /// to `cpu` (see [`pin_to_cpu`]) before issuing the ioctl.
+    pub(crate) struct Handle {
+        device: File,
+        cpu: u16,
+    }
+
+    impl Handle {
+        /// The CPU this handle was opened for.
+        pub(crate) fn cpu(&self) -> u16 {
+            self.cpu
+        }
+    }
+
+    impl Backend for Handle {
+        fn open(cpu: u16) -> Result<Self> {
+            let path = "/dev/DirectHW";
+            if !Path::new(path).exists() {
+                return Err(MsrError::MissingKernelModule);
+            }
+            Ok(Self {
+                device: OpenOptions::new().read(true).write(true).open(path)?,
+                cpu,
+            })
+        }
+
+        fn read(&mut self, reg: u32) -> Result<u64> {
+            pin_to_cpu(self.cpu())?;
+            let mut request = DirectHwMsr { ecx: reg, lo: 0, hi: 0 };
+            let rc = unsafe { ioctl(self.device.as_raw_fd(), DIRECTHW_RDMSR, &mut request) };
+            if rc != 0 {
+                return Err(MsrError::IoError(std::io::Error::last_os_error()));
+            }
+            Ok(((request.hi as u64) << 32) | request.lo as u64)
+        }
+
+        fn write(&self, reg: u32, value: u64) -> Result<()> {
+            pin_to_cpu(self.cpu())?;
+            let request = DirectHwMsr {
+                ecx: reg,
+                lo: value as u32,
+                hi: (value >> 32) as u32,
+            };
+            let rc = unsafe { ioctl(self.device.as_raw_fd(), DIRECTHW_WRMSR, &request) };
+            if rc != 0 {
+                return Err(MsrError::IoError(std::io::Error::last_os_error()));
+            }
+            Ok(())
+        }
+    }
+
+    pub(crate) fn enumerate_cpus() -> Result<Vec<u16>> {
+        extern "C" {
+            fn sysconf(name: i32) -> i64;
+        }
+        const _SC_NPROCESSORS_ONLN: i32 = 58;
+
+        let count = unsafe { sysconf(_SC_NPROCESSORS_ONLN) };
+        if count <= 0 {
+            return Err(MsrError::MissingKernelModule);
+        }
+        Ok((0..count as u16).collect())
+    }
+}
+
+pub(crate) use platform::Handle;
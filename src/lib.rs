@@ -2,16 +2,21 @@
 
 //! As most of the existing crates require kernel-mode, this provides a
 //! Rust-friendly interface for reading and writing to MSRs while in
-//! user-space. This does require the `msr` kernel module to be loaded.
+//! user-space. This does require the platform's MSR access facility
+//! (the `msr` kernel module on Linux, or the equivalent device on other
+//! supported OSes) to be present.
 //!
-//! Currently this crate only supports Linux.
+//! Linux, FreeBSD, DragonFly BSD, OpenBSD and macOS are supported; the
+//! OS-specific plumbing lives behind the `Backend` trait in the private
+//! `backend` module and is selected automatically for the target
+//! platform.
 
+mod backend;
+
+use backend::Backend;
 use std::{
+    collections::{btree_map::Entry, BTreeMap},
     convert::From,
-    fs::{File, OpenOptions},
-    io::{Read, Seek, SeekFrom},
-    os::unix::fs::FileExt,
-    path::Path,
 };
 
 #[derive(Debug)]
@@ -19,6 +24,8 @@ pub enum MsrError {
     IoError(std::io::Error),
     MissingKernelModule,
     UnknownError,
+    /// A register range overflowed `u32` before the access completed.
+    InvalidRange,
 }
 
 impl std::error::Error for MsrError {}
@@ -29,6 +36,7 @@ impl std::fmt::Display for MsrError {
             MsrError::IoError(io_error) => write!(f, "IoError Encountered: {io_error}"),
             MsrError::MissingKernelModule => write!(f, "MSR Kernel Module not loaded!"),
             MsrError::UnknownError => write!(f, "An unknown error was encountered!"),
+            MsrError::InvalidRange => write!(f, "Register range overflowed a u32 address!"),
         }
     }
 }
@@ -45,23 +53,21 @@ type Result<T> = std::result::Result<T, MsrError>;
 pub struct Msr {
     /// A model specific register address we would like to read.
     pub reg: u32,
-    fh: File,
+    fh: backend::Handle,
     buffer: [u8; 8],
 }
 
 impl Msr {
     /// Construct an Msr for a specified register and CPU.
+    ///
+    /// On macOS, `cpu` pinning is best-effort: the DirectHW backend tags
+    /// the calling thread with a `THREAD_AFFINITY_POLICY` affinity hint,
+    /// which the scheduler is free to ignore, so a read or write may land
+    /// on a different core than requested.
     pub fn new(reg: u32, cpu: u16) -> Result<Self> {
-        let cpu_msr_path: String = format!("/dev/cpu/{cpu}/msr");
-        if !Path::new(&cpu_msr_path).exists() {
-            return Err(MsrError::MissingKernelModule);
-        }
         Ok(Self {
             reg,
-            fh: OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(cpu_msr_path)?,
+            fh: backend::Handle::open(cpu)?,
             buffer: [0; 8],
         })
     }
@@ -76,6 +82,81 @@ impl Msr {
     pub fn set_value(&mut self, value: u64) {
         self.buffer = value.to_ne_bytes();
     }
+
+    /// Read `count` consecutive registers starting at `base` in a single
+    /// pass, rather than reopening/reseeking once per register.
+    pub fn read_range(&mut self, base: u32, count: usize) -> Result<Vec<u64>> {
+        self.fh.read_range(base, count)
+    }
+
+    /// Write `values` to `count` consecutive registers starting at `base`
+    /// in a single pass. See [`Msr::read_range`].
+    pub fn write_range(&self, base: u32, values: &[u64]) -> Result<()> {
+        self.fh.write_range(base, values)
+    }
+
+    /// Decode the cached buffer into the conventional `(EAX, EDX)`
+    /// low/high register pair `rdmsr` returns.
+    pub fn read_lo_hi(&mut self) -> Result<(u32, u32)> {
+        let value = self.read_value();
+        Ok((value as u32, (value >> 32) as u32))
+    }
+
+    /// Encode a conventional `(EAX, EDX)` low/high pair into the cached
+    /// buffer, matching `wrmsr` semantics. Call [`Accessor::write`] to
+    /// commit it to the MSR.
+    pub fn set_lo_hi(&mut self, lo: u32, hi: u32) {
+        self.set_value(((hi as u64) << 32) | lo as u64);
+    }
+}
+
+/// Reads and writes a single MSR across every online CPU.
+///
+/// CPUs are discovered once via the platform's MSR backend; the handle
+/// for a given CPU is only opened the first time that CPU is touched, and
+/// is then kept around in `handles` so repeated reads don't pay the cost
+/// of reopening and reseeking a file per call.
+pub struct MsrStore {
+    cpus: Vec<u16>,
+    handles: BTreeMap<u16, backend::Handle>,
+}
+
+impl MsrStore {
+    /// Enumerate every CPU that exposes an MSR interface on this platform.
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            cpus: backend::enumerate_cpus()?,
+            handles: BTreeMap::new(),
+        })
+    }
+
+    /// Read `reg` on every CPU known to this store.
+    pub fn read_all(&mut self, reg: u32) -> Result<BTreeMap<u16, u64>> {
+        let cpus = self.cpus.clone();
+        let mut values = BTreeMap::new();
+        for cpu in cpus {
+            values.insert(cpu, self.read_on(reg, cpu)?);
+        }
+        Ok(values)
+    }
+
+    /// Read `reg` on a single CPU known to this store.
+    pub fn read_on(&mut self, reg: u32, cpu: u16) -> Result<u64> {
+        self.handle(cpu)?.read(reg)
+    }
+
+    /// Write `value` to `reg` on a single CPU known to this store.
+    pub fn write_on(&mut self, reg: u32, cpu: u16, value: u64) -> Result<()> {
+        self.handle(cpu)?.write(reg, value)
+    }
+
+    /// Return the cached handle for `cpu`, opening it on first use.
+    fn handle(&mut self, cpu: u16) -> Result<&mut backend::Handle> {
+        match self.handles.entry(cpu) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(backend::Handle::open(cpu)?)),
+        }
+    }
 }
 
 pub trait Accessor {
@@ -87,16 +168,211 @@ impl Accessor for Msr {
     /// Read the bytes from the MSR at the specified CPU and return the value.
     /// - Expects the a file-handle to have already been opened.
     fn read(&mut self) -> Result<u64> {
-        self.fh.seek(SeekFrom::Start(self.reg.into()))?;
-        self.fh.read_exact(&mut self.buffer)?;
-        Ok(self.read_value())
+        let value = self.fh.read(self.reg)?;
+        self.buffer = value.to_ne_bytes();
+        Ok(value)
     }
 
     /// Write the bytes buffer into the MSR at the specified CPU.
     /// Expects the a file-handle to have already been opened.
     fn write(&self) -> Result<()> {
         // Make sure the buffer is updated for writing.
-        self.fh.write_all_at(&self.buffer, self.reg.into())?;
-        Ok(())
+        self.fh.write(self.reg, u64::from_ne_bytes(self.buffer))
+    }
+}
+
+/// A named bitfield within an MSR's 64-bit value.
+#[derive(Debug, Clone, Copy)]
+pub struct Field {
+    pub mask: u64,
+    pub shift: u32,
+}
+
+impl Field {
+    /// Construct a field occupying the bits set in `mask`, starting at
+    /// `shift`.
+    pub const fn new(mask: u64, shift: u32) -> Self {
+        Self { mask, shift }
+    }
+
+    /// Extract this field out of a raw MSR value.
+    pub fn extract(&self, value: u64) -> u64 {
+        (value & self.mask) >> self.shift
+    }
+
+    /// Splice `field_value` into `value`, leaving every bit outside of
+    /// this field untouched.
+    pub fn splice(&self, value: u64, field_value: u64) -> u64 {
+        (value & !self.mask) | ((field_value << self.shift) & self.mask)
+    }
+}
+
+/// Declare a set of named [`Field`]s, typically the layout of one MSR.
+///
+/// ```ignore
+/// register_fields! {
+///     TEMPERATURE_TARGET = Field::new(0x0000_0000_0000_ff00, 8);
+/// }
+/// ```
+#[macro_export]
+macro_rules! register_fields {
+    ($($name:ident = $field:expr;)*) => {
+        $(pub const $name: $crate::Field = $field;)*
+    };
+}
+
+impl Msr {
+    /// Decode a single field out of the cached buffer, without touching
+    /// the hardware.
+    pub fn read_field(&mut self, field: Field) -> u64 {
+        field.extract(self.read_value())
+    }
+
+    /// Read-modify-write a single field in the cached buffer, leaving
+    /// every other bit (including reserved ones) untouched. Call
+    /// [`Accessor::write`] to commit the result to the MSR.
+    pub fn modify_field(&mut self, field: Field, value: u64) {
+        let current = self.read_value();
+        self.set_value(field.splice(current, value));
+    }
+}
+
+/// Per-register behavior for a [`PolicyMsr`]: either pass the access
+/// straight through to hardware, or emulate it against a shadow value
+/// without touching `/dev/cpu/*/msr` at all.
+#[derive(Debug)]
+pub enum MsrPolicy {
+    /// Reads and writes hit the real MSR, as a bare [`Msr`] would.
+    Passthrough,
+    /// Reads return the shadow value; writes update the shadow only.
+    Emulate(std::cell::Cell<u64>),
+}
+
+impl MsrPolicy {
+    /// Build an emulated policy seeded with `value`.
+    pub fn emulate(value: u64) -> Self {
+        Self::Emulate(std::cell::Cell::new(value))
+    }
+}
+
+/// Wraps an [`Msr`] with a [`MsrPolicy`] deciding whether reads/writes hit
+/// hardware or are absorbed into a shadow value, so VMMs and test
+/// harnesses can stub specific registers (e.g. `0x1a2,type=r,action=emulate`)
+/// while passing the rest straight through the same [`Accessor`] trait.
+pub struct PolicyMsr {
+    msr: Msr,
+    policy: MsrPolicy,
+}
+
+impl PolicyMsr {
+    /// Wrap `msr` with `policy`.
+    pub fn new(msr: Msr, policy: MsrPolicy) -> Self {
+        Self { msr, policy }
+    }
+
+    /// The policy currently applied to this MSR.
+    pub fn policy(&self) -> &MsrPolicy {
+        &self.policy
+    }
+
+    /// Replace the policy applied to this MSR.
+    pub fn set_policy(&mut self, policy: MsrPolicy) {
+        self.policy = policy;
+    }
+}
+
+impl Accessor for PolicyMsr {
+    /// Emulated MSRs return the shadow value without touching hardware;
+    /// passthrough MSRs read the real register as today.
+    fn read(&mut self) -> Result<u64> {
+        match &self.policy {
+            MsrPolicy::Emulate(shadow) => {
+                let value = shadow.get();
+                self.msr.set_value(value);
+                Ok(value)
+            }
+            MsrPolicy::Passthrough => self.msr.read(),
+        }
+    }
+
+    /// Emulated writes update the shadow value only; passthrough writes
+    /// hit the real register as today.
+    fn write(&self) -> Result<()> {
+        match &self.policy {
+            MsrPolicy::Emulate(shadow) => {
+                shadow.set(u64::from_ne_bytes(self.msr.buffer));
+                Ok(())
+            }
+            MsrPolicy::Passthrough => self.msr.write(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An `Msr` wrapping an already-open file instead of a real MSR
+    /// device, for tests that only exercise the cached-buffer logic.
+    #[cfg(target_os = "linux")]
+    fn test_msr() -> Msr {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")
+            .expect("/dev/null should always be openable");
+        Msr {
+            reg: 0,
+            fh: backend::Handle::from_file(file),
+            buffer: [0; 8],
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn lo_hi_round_trips_through_the_cached_buffer() {
+        let mut msr = test_msr();
+
+        msr.set_lo_hi(0x1111_2222, 0x3333_4444);
+
+        assert_eq!(msr.read_lo_hi().unwrap(), (0x1111_2222, 0x3333_4444));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn emulate_policy_reads_and_writes_the_shadow_without_touching_hardware() {
+        let mut policy_msr = PolicyMsr::new(test_msr(), MsrPolicy::emulate(0x42));
+
+        assert_eq!(policy_msr.read().unwrap(), 0x42);
+
+        policy_msr.msr.set_value(0x99);
+        policy_msr.write().unwrap();
+        match policy_msr.policy() {
+            MsrPolicy::Emulate(shadow) => assert_eq!(shadow.get(), 0x99),
+            MsrPolicy::Passthrough => panic!("expected Emulate"),
+        }
+
+        // The backing handle is `/dev/null`, which can't satisfy an 8-byte
+        // `read_exact`; a passing `read()` here proves the hardware path
+        // was never touched by the Emulate policy.
+        assert_eq!(policy_msr.read().unwrap(), 0x99);
+    }
+
+    #[test]
+    fn field_splice_preserves_bits_outside_the_mask() {
+        let field = Field::new(0x0000_0000_0000_ff00, 8);
+        let original = 0xdead_beef_1234_56ff;
+
+        let spliced = field.splice(original, 0xab);
+
+        assert_eq!(field.extract(spliced), 0xab);
+        assert_eq!(spliced & !field.mask, original & !field.mask);
+    }
+
+    #[test]
+    fn field_extract_reads_back_what_splice_wrote() {
+        let field = Field::new(0xff00, 8);
+        let value = field.splice(0, 0x7f);
+        assert_eq!(field.extract(value), 0x7f);
     }
 }